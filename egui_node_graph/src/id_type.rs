@@ -1,3 +1,4 @@
+use std::cell::RefCell;
 use std::ops::{Index, IndexMut};
 
 use slotmap::{Key, SecondaryMap, SlotMap};
@@ -8,6 +9,29 @@ slotmap::new_key_type! { pub struct OutputIdInternal; }
 
 pub type MapId = u128;
 
+/// Returned by the `try_*` accessors on [`UniqueSecondaryMap`] when the key
+/// passed in belongs to a different map than the one it was used on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WrongMapError {
+    /// The `MapId` the container expected, i.e. its own id.
+    pub expected: MapId,
+    /// The `MapId` actually stamped on the offending key.
+    pub found: MapId,
+}
+
+impl std::fmt::Display for WrongMapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "key belongs to map {}, but this map is {}",
+            self.found, self.expected
+        )
+    }
+}
+
+impl std::error::Error for WrongMapError {}
+
+#[cfg_attr(feature = "persistence", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Copy, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
 pub struct UniqueId<K: Key>(K, MapId);
 
@@ -50,16 +74,41 @@ impl From<InputId> for AnyParameterId {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(
+    feature = "persistence",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(bound(
+        serialize = "SlotMap<K::InnerKey, V>: serde::Serialize, K::InnerKey: serde::Serialize",
+        deserialize = "SlotMap<K::InnerKey, V>: serde::Deserialize<'de>, K::InnerKey: serde::Deserialize<'de>"
+    ))
+)]
 pub struct UniqueSlotmap<K, V>
 where
     K: HasKey,
     K::InnerKey: Key,
 {
     map: SlotMap<K::InnerKey, V>,
+    // The `id` is serialized alongside the map so that `UniqueId`s saved
+    // elsewhere (e.g. connection tables) remain valid after a load, instead
+    // of being invalidated by a freshly-generated id.
     id: MapId,
+    // Insertion order of the currently-live keys, used by `iter_ordered` /
+    // `keys_ordered` to give a traversal that's stable across slot churn.
+    // Trimmed on both `remove` and `retain` so it can't grow unbounded over
+    // an edit-heavy session; `iter_ordered`/`keys_ordered` still filter
+    // defensively in case `order` ever holds a key no longer in the map.
+    order: Vec<K::InnerKey>,
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(
+    feature = "persistence",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(bound(
+        serialize = "SecondaryMap<K::InnerKey, V>: serde::Serialize",
+        deserialize = "SecondaryMap<K::InnerKey, V>: serde::Deserialize<'de>"
+    ))
+)]
 pub struct UniqueSecondaryMap<K, V>
 where
     K: HasKey,
@@ -93,6 +142,7 @@ where
         Self {
             map: SlotMap::with_key(),
             id: get_random_map_id(),
+            order: Vec::new(),
         }
     }
 }
@@ -159,7 +209,13 @@ impl<K: Key, V> UniqueSlotmap<UniqueId<K>, V> {
 
     pub fn remove(&mut self, index: UniqueId<K>) -> Option<V> {
         let key = self.check_key(index);
-        key.and_then(|key| self.map.remove(key))
+        let value = key.and_then(|key| self.map.remove(key));
+        if value.is_some() {
+            if let Some(key) = key {
+                self.order.retain(|existing| *existing != key);
+            }
+        }
+        value
     }
 
     pub fn iter(&self) -> impl Iterator<Item = (UniqueId<K>, &V)> + '_ {
@@ -181,6 +237,7 @@ impl<K: Key, V> UniqueSlotmap<UniqueId<K>, V> {
     {
         let id = self.id;
         let key = self.map.insert_with_key(|callback_key| f(UniqueId(callback_key, id)));
+        self.order.push(key);
         UniqueId(key, id)
     }
 
@@ -189,7 +246,27 @@ impl<K: Key, V> UniqueSlotmap<UniqueId<K>, V> {
         F: FnMut(UniqueId<K>, &mut V) -> bool,
     {
         let id = self.id;
-        self.map.retain(|key, value| f(UniqueId(key, id), value))
+        self.map.retain(|key, value| f(UniqueId(key, id), value));
+        self.order.retain(|key| self.map.contains_key(*key));
+    }
+
+    /// Iterates over the map's entries in the order they were inserted,
+    /// rather than slotmap's internal slot order. Useful for reproducible
+    /// serialization, stable z-ordering in the UI, and diffable save files.
+    pub fn iter_ordered(&self) -> impl Iterator<Item = (UniqueId<K>, &V)> + '_ {
+        let id = self.id;
+        self.order
+            .iter()
+            .filter_map(move |key| self.map.get(*key).map(|value| (UniqueId(*key, id), value)))
+    }
+
+    /// Like [`Self::keys`], but in insertion order. See [`Self::iter_ordered`].
+    pub fn keys_ordered(&self) -> impl Iterator<Item = UniqueId<K>> + '_ {
+        let id = self.id;
+        self.order
+            .iter()
+            .filter(move |key| self.map.contains_key(**key))
+            .map(move |key| UniqueId(*key, id))
     }
 
     pub fn contains_key(&self, index: UniqueId<K>) -> bool {
@@ -205,6 +282,56 @@ impl<K: Key, V> UniqueSlotmap<UniqueId<K>, V> {
             .keys()
             .map(move |k| (UniqueId(k, self.id)))
     }
+
+    /// Moves every value out of `source` and into `self`, allocating a fresh
+    /// id in `self` for each one. Returns a translation table from the old
+    /// `UniqueId` (in `source`) to the new one (in `self`), which a caller
+    /// can pass to [`UniqueSecondaryMap::remap`] to carry side-tables (e.g.
+    /// connections, metadata) across the merge.
+    ///
+    /// This is the supported way to splice one graph's storage into
+    /// another: pasting a copied subgraph, merging a library fragment, or
+    /// combining undo fragments, none of which can otherwise cross the
+    /// `MapId` isolation boundary.
+    pub fn append_remapped(&mut self, mut source: Self) -> UniqueSecondaryMap<UniqueId<K>, UniqueId<K>> {
+        let mut translation = UniqueSecondaryMap::new_from_key(&source);
+        // Walk `source` in insertion order (not slotmap's internal slot
+        // order) so the destination map's relative ordering of the merged-in
+        // entries matches the order they were created in, e.g. for stable
+        // z-ordering of pasted nodes in the UI.
+        for old_id in source.keys_ordered().collect::<Vec<_>>() {
+            let value = source.remove(old_id).expect("key was just read from the map");
+            let new_id = self.insert(value);
+            translation.insert(old_id, new_id);
+        }
+        translation
+    }
+}
+
+// slotmap itself has no rayon integration, so these bridge the existing
+// serial iterators onto rayon's thread pool via `ParallelBridge` rather than
+// relying on parallel iterators the underlying `SlotMap`/`SecondaryMap`
+// don't provide.
+#[cfg(feature = "rayon")]
+impl<K, V> UniqueSlotmap<UniqueId<K>, V>
+where
+    K: Key + Send + Sync,
+    V: Send + Sync,
+{
+    pub fn par_iter(&self) -> impl rayon::iter::ParallelIterator<Item = (UniqueId<K>, &V)> {
+        use rayon::iter::ParallelBridge;
+        self.iter().par_bridge()
+    }
+
+    pub fn par_iter_mut(&mut self) -> impl rayon::iter::ParallelIterator<Item = (UniqueId<K>, &mut V)> {
+        use rayon::iter::ParallelBridge;
+        self.iter_mut().par_bridge()
+    }
+
+    pub fn par_keys(&self) -> impl rayon::iter::ParallelIterator<Item = UniqueId<K>> + '_ {
+        use rayon::iter::ParallelBridge;
+        self.keys().par_bridge()
+    }
 }
 
 // UniqueSecondaryMap
@@ -264,6 +391,50 @@ impl<K: Key, V> UniqueSecondaryMap<UniqueId<K>, V> {
         }
     }
 
+    fn wrong_map_error(&self, key: UniqueId<K>) -> WrongMapError {
+        let UniqueId(_, found) = key;
+        WrongMapError {
+            expected: self.id,
+            found,
+        }
+    }
+
+    /// Like [`Self::get`], but distinguishes "key belongs to another map"
+    /// from "key not present in this map" instead of treating both as `None`.
+    pub fn try_get(&self, index: UniqueId<K>) -> Result<Option<&V>, WrongMapError> {
+        match self.check_key(index) {
+            Some(key) => Ok(self.map.get(key)),
+            None => Err(self.wrong_map_error(index)),
+        }
+    }
+
+    /// Like [`Self::get_mut`], but returns a [`WrongMapError`] instead of
+    /// silently yielding `None` when `index` belongs to another map.
+    pub fn try_get_mut(&mut self, index: UniqueId<K>) -> Result<Option<&mut V>, WrongMapError> {
+        match self.check_key(index) {
+            Some(key) => Ok(self.map.get_mut(key)),
+            None => Err(self.wrong_map_error(index)),
+        }
+    }
+
+    /// Like [`Self::insert`], but returns a [`WrongMapError`] instead of
+    /// panicking when `key` belongs to another map.
+    pub fn try_insert(&mut self, key: UniqueId<K>, value: V) -> Result<Option<V>, WrongMapError> {
+        match self.check_key(key) {
+            Some(key) => Ok(self.map.insert(key, value)),
+            None => Err(self.wrong_map_error(key)),
+        }
+    }
+
+    /// Like [`Self::remove`], but returns a [`WrongMapError`] instead of
+    /// silently yielding `None` when `index` belongs to another map.
+    pub fn try_remove(&mut self, index: UniqueId<K>) -> Result<Option<V>, WrongMapError> {
+        match self.check_key(index) {
+            Some(key) => Ok(self.map.remove(key)),
+            None => Err(self.wrong_map_error(index)),
+        }
+    }
+
     pub fn retain<F>(&mut self, mut f: F)
     where
         F: FnMut(UniqueId<K>, &mut V) -> bool,
@@ -285,6 +456,178 @@ impl<K: Key, V> UniqueSecondaryMap<UniqueId<K>, V> {
             .keys()
             .map(move |k| (UniqueId(k, self.id)))
     }
+
+    /// Rewrites this map's *keys* through a translation table produced by
+    /// [`UniqueSlotmap::append_remapped`], moving every entry whose old key
+    /// is present in `translation` into a new map stamped with `dest`'s id.
+    /// Entries whose key has no corresponding translation are dropped.
+    ///
+    /// This does not touch `V`. If the values are themselves `UniqueId`s
+    /// into the source map (e.g. a connections table mapping one id to
+    /// another), use [`Self::remap_ids`] instead so both sides are
+    /// translated.
+    pub fn remap<V2>(self, translation: &UniqueSecondaryMap<UniqueId<K>, UniqueId<K>>, dest: &UniqueSlotmap<UniqueId<K>, V2>) -> Self {
+        let mut out = Self::new_from_key(dest);
+        let id = self.id;
+        for (old_key, value) in self.map.into_iter() {
+            let old_id = UniqueId(old_key, id);
+            if let Some(&new_id) = translation.get(old_id) {
+                out.insert(new_id, value);
+            }
+        }
+        out
+    }
+}
+
+impl<K: Key> UniqueSecondaryMap<UniqueId<K>, UniqueId<K>> {
+    /// Like [`Self::remap`], but for id-valued side-tables (e.g. a
+    /// connections table mapping one id to another): rewrites both the key
+    /// and the value through `translation`, dropping entries where either
+    /// side has no corresponding translation.
+    pub fn remap_ids<V2>(self, translation: &UniqueSecondaryMap<UniqueId<K>, UniqueId<K>>, dest: &UniqueSlotmap<UniqueId<K>, V2>) -> Self {
+        let mut out = Self::new_from_key(dest);
+        let id = self.id;
+        for (old_key, old_value) in self.map.into_iter() {
+            let old_id = UniqueId(old_key, id);
+            let new_id = translation.get(old_id);
+            let new_value = translation.get(old_value);
+            if let (Some(&new_id), Some(&new_value)) = (new_id, new_value) {
+                out.insert(new_id, new_value);
+            }
+        }
+        out
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl<K, V> UniqueSecondaryMap<UniqueId<K>, V>
+where
+    K: Key + Send + Sync,
+    V: Send + Sync,
+{
+    pub fn par_iter(&self) -> impl rayon::iter::ParallelIterator<Item = (UniqueId<K>, &V)> {
+        use rayon::iter::ParallelBridge;
+        self.iter().par_bridge()
+    }
+
+    pub fn par_iter_mut(&mut self) -> impl rayon::iter::ParallelIterator<Item = (UniqueId<K>, &mut V)> {
+        use rayon::iter::ParallelBridge;
+        self.iter_mut().par_bridge()
+    }
+
+    pub fn par_keys(&self) -> impl rayon::iter::ParallelIterator<Item = UniqueId<K>> + '_ {
+        use rayon::iter::ParallelBridge;
+        self.keys().par_bridge()
+    }
+}
+
+// TrackingUniqueSlotmap
+
+/// A [`UniqueSlotmap`] that remembers which keys have been read through
+/// `get`/`get_mut`/indexing since the last [`Self::clear_gotten`].
+///
+/// This is meant for incremental/memoized graph evaluation: an evaluator
+/// records which node outputs it actually read while computing a result,
+/// then after an edit it only needs to recompute nodes whose recorded read
+/// set overlaps the changed ids, instead of re-running the whole graph.
+///
+/// The read log is kept in a `RefCell` so that reads (`get`, indexing) can
+/// be recorded without requiring `&mut self` — callers that only ever read
+/// the graph still need a way to observe what they read.
+#[derive(Debug, Clone)]
+pub struct TrackingUniqueSlotmap<K, V>
+where
+    K: HasKey,
+    K::InnerKey: Key,
+{
+    inner: UniqueSlotmap<K, V>,
+    gotten: RefCell<UniqueSecondaryMap<K, ()>>,
+    dirty: UniqueSecondaryMap<K, ()>,
+}
+
+impl<K, V> Default for TrackingUniqueSlotmap<K, V>
+where
+    K: HasKey,
+    K::InnerKey: Key,
+{
+    fn default() -> Self {
+        let inner = UniqueSlotmap::default();
+        Self {
+            gotten: RefCell::new(UniqueSecondaryMap::new_from_key(&inner)),
+            dirty: UniqueSecondaryMap::new_from_key(&inner),
+            inner,
+        }
+    }
+}
+
+impl<K: Key, V> Index<UniqueId<K>> for TrackingUniqueSlotmap<UniqueId<K>, V> {
+    type Output = V;
+    fn index(&self, index: UniqueId<K>) -> &Self::Output {
+        self.get(index).expect("Attempted to access key from another map or not in this map")
+    }
+}
+
+impl<K: Key, V> IndexMut<UniqueId<K>> for TrackingUniqueSlotmap<UniqueId<K>, V> {
+    fn index_mut(&mut self, index: UniqueId<K>) -> &mut Self::Output {
+        self.get_mut(index).expect("Attempted to access key from another map or not in this map")
+    }
+}
+
+impl<K: Key, V> TrackingUniqueSlotmap<UniqueId<K>, V> {
+    pub fn get(&self, index: UniqueId<K>) -> Option<&V> {
+        let value = self.inner.get(index);
+        if value.is_some() {
+            self.gotten.borrow_mut().insert(index, ());
+        }
+        value
+    }
+
+    pub fn get_mut(&mut self, index: UniqueId<K>) -> Option<&mut V> {
+        let value = self.inner.get_mut(index);
+        if value.is_some() {
+            self.gotten.borrow_mut().insert(index, ());
+            self.dirty.insert(index, ());
+        }
+        value
+    }
+
+    pub fn insert(&mut self, value: V) -> UniqueId<K> {
+        self.inner.insert(value)
+    }
+
+    pub fn remove(&mut self, index: UniqueId<K>) -> Option<V> {
+        self.gotten.borrow_mut().remove(index);
+        self.dirty.remove(index);
+        self.inner.remove(index)
+    }
+
+    /// Whether `id` has been read (via `get`, `get_mut`, or indexing) since
+    /// the last [`Self::clear_gotten`].
+    pub fn key_gotten(&self, id: UniqueId<K>) -> bool {
+        self.gotten.borrow().contains_key(id)
+    }
+
+    pub fn gotten_keys(&self) -> impl Iterator<Item = UniqueId<K>> {
+        self.gotten.borrow().keys().collect::<Vec<_>>().into_iter()
+    }
+
+    pub fn clear_gotten(&self) {
+        self.gotten.borrow_mut().retain(|_, _| false);
+    }
+
+    /// Whether `id` has been mutated (via `get_mut` or indexed assignment)
+    /// since the last [`Self::clear_dirty`].
+    pub fn key_dirty(&self, id: UniqueId<K>) -> bool {
+        self.dirty.contains_key(id)
+    }
+
+    pub fn dirty_keys(&self) -> impl Iterator<Item = UniqueId<K>> + '_ {
+        self.dirty.keys()
+    }
+
+    pub fn clear_dirty(&mut self) {
+        self.dirty.retain(|_, _| false);
+    }
 }
 
 /*
@@ -298,3 +641,169 @@ where
 }
 
 */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "persistence")]
+    #[test]
+    fn slotmap_round_trip_preserves_map_id_and_keys() {
+        let mut map: UniqueSlotmap<NodeId, String> = UniqueSlotmap::default();
+        let a = map.insert("a".to_string());
+        let b = map.insert("b".to_string());
+
+        let encoded = serde_json::to_string(&map).unwrap();
+        let decoded: UniqueSlotmap<NodeId, String> = serde_json::from_str(&encoded).unwrap();
+
+        // The ids created before the round-trip must still index correctly
+        // after it; regenerating a random MapId on load would invalidate them.
+        assert_eq!(decoded.get(a), Some(&"a".to_string()));
+        assert_eq!(decoded.get(b), Some(&"b".to_string()));
+    }
+
+    #[cfg(feature = "persistence")]
+    #[test]
+    fn secondary_map_round_trip_preserves_map_id_and_keys() {
+        let mut nodes: UniqueSlotmap<NodeId, &'static str> = UniqueSlotmap::default();
+        let a = nodes.insert("a");
+
+        let mut side_table = UniqueSecondaryMap::new_from_key(&nodes);
+        side_table.insert(a, 42);
+
+        let encoded = serde_json::to_string(&side_table).unwrap();
+        let decoded: UniqueSecondaryMap<NodeId, i32> = serde_json::from_str(&encoded).unwrap();
+
+        assert_eq!(decoded.get(a), Some(&42));
+    }
+
+    #[test]
+    fn remove_trims_insertion_order_instead_of_growing_unbounded() {
+        let mut map: UniqueSlotmap<NodeId, i32> = UniqueSlotmap::default();
+        for i in 0..1000 {
+            let id = map.insert(i);
+            map.remove(id);
+        }
+        assert_eq!(map.keys_ordered().count(), 0);
+        assert_eq!(map.iter_ordered().count(), 0);
+        assert_eq!(map.order.len(), 0);
+    }
+
+    #[test]
+    fn tracking_slotmap_records_reads_through_shared_reference() {
+        let mut map: TrackingUniqueSlotmap<NodeId, &'static str> = TrackingUniqueSlotmap::default();
+        let a = map.insert("a");
+        let b = map.insert("b");
+
+        // `get` and indexing only need `&self`; both must still be recorded.
+        let tracker: &TrackingUniqueSlotmap<NodeId, &'static str> = &map;
+        assert_eq!(tracker.get(a), Some(&"a"));
+        let _ = &tracker[b];
+
+        assert!(tracker.key_gotten(a));
+        assert!(tracker.key_gotten(b));
+        assert!(!tracker.key_dirty(a));
+        assert!(!tracker.key_dirty(b));
+
+        map.clear_gotten();
+        assert!(!map.key_gotten(a));
+    }
+
+    #[test]
+    fn tracking_slotmap_marks_mutated_keys_dirty() {
+        let mut map: TrackingUniqueSlotmap<NodeId, i32> = TrackingUniqueSlotmap::default();
+        let a = map.insert(1);
+
+        *map.get_mut(a).unwrap() += 1;
+
+        assert!(map.key_gotten(a));
+        assert!(map.key_dirty(a));
+
+        map.clear_dirty();
+        assert!(!map.key_dirty(a));
+    }
+
+    #[test]
+    fn try_accessors_succeed_for_keys_from_the_same_map() {
+        let mut nodes: UniqueSlotmap<NodeId, &'static str> = UniqueSlotmap::default();
+        let a = nodes.insert("a");
+
+        let mut side_table = UniqueSecondaryMap::new_from_key(&nodes);
+        assert_eq!(side_table.try_insert(a, 1), Ok(None));
+        assert_eq!(side_table.try_get(a), Ok(Some(&1)));
+        assert_eq!(side_table.try_get_mut(a), Ok(Some(&mut 1)));
+        assert_eq!(side_table.try_remove(a), Ok(Some(1)));
+        assert_eq!(side_table.try_get(a), Ok(None));
+    }
+
+    #[test]
+    fn try_accessors_report_wrong_map_error_for_foreign_keys() {
+        let mut nodes_a: UniqueSlotmap<NodeId, &'static str> = UniqueSlotmap::default();
+        let foreign_id = nodes_a.insert("a");
+
+        let nodes_b: UniqueSlotmap<NodeId, &'static str> = UniqueSlotmap::default();
+        let mut side_table = UniqueSecondaryMap::new_from_key(&nodes_b);
+
+        let err = side_table.try_insert(foreign_id, 1).unwrap_err();
+        assert_eq!(err.found, foreign_id.1);
+        assert_ne!(err.expected, err.found);
+
+        assert!(side_table.try_get(foreign_id).is_err());
+        assert!(side_table.try_get_mut(foreign_id).is_err());
+        assert!(side_table.try_remove(foreign_id).is_err());
+    }
+
+    #[test]
+    fn append_remapped_preserves_insertion_order_and_remaps_side_tables() {
+        let mut source: UniqueSlotmap<NodeId, &'static str> = UniqueSlotmap::default();
+        let source_a = source.insert("a");
+        let source_b = source.insert("b");
+        let source_c = source.insert("c");
+
+        let mut connections = UniqueSecondaryMap::new_from_key(&source);
+        connections.insert(source_a, source_c);
+
+        let mut dest: UniqueSlotmap<NodeId, &'static str> = UniqueSlotmap::default();
+        let dest_existing = dest.insert("existing");
+
+        let translation = dest.append_remapped(source);
+
+        // Merged-in entries keep their relative insertion order.
+        let merged: Vec<&str> = dest
+            .iter_ordered()
+            .map(|(_, value)| *value)
+            .collect();
+        assert_eq!(merged, vec!["existing", "a", "b", "c"]);
+        assert_eq!(dest.get(dest_existing), Some(&"existing"));
+
+        // The connections table is id-valued, so both its keys and values
+        // need to be translated; plain `remap` would leave stale source ids
+        // as values.
+        let remapped_connections = connections.remap_ids(&translation, &dest);
+        let new_a = translation.get(source_a).copied().unwrap();
+        let new_b = translation.get(source_b).copied().unwrap();
+        let new_c = translation.get(source_c).copied().unwrap();
+        assert_eq!(remapped_connections.get(new_a), Some(&new_c));
+        assert!(dest.contains_key(new_b));
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn par_iter_visits_the_same_entries_as_iter() {
+        use rayon::iter::ParallelIterator;
+        use std::collections::BTreeSet;
+
+        let mut map: UniqueSlotmap<NodeId, i32> = UniqueSlotmap::default();
+        for i in 0..8 {
+            map.insert(i);
+        }
+
+        let serial: BTreeSet<i32> = map.iter().map(|(_, v)| *v).collect();
+        let parallel: BTreeSet<i32> = map.par_iter().map(|(_, v)| *v).collect();
+        assert_eq!(serial, parallel);
+
+        let serial_keys: BTreeSet<NodeId> = map.keys().collect();
+        let parallel_keys: BTreeSet<NodeId> = map.par_keys().collect();
+        assert_eq!(serial_keys, parallel_keys);
+    }
+}